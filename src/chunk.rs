@@ -0,0 +1,214 @@
+//! 基于 FastCDC 的内容定义分块 (content-defined chunking)
+//!
+//! 用于把一个 layer blob 切分成若干个"内容边界"稳定的分块, 使得 blob 内部的局部
+//! 修改只会影响修改点附近的少数分块, 而不是让整份 blob 的哈希都发生变化。
+
+use sha2::{Digest, Sha256};
+
+/// Gear 表: 256 个固定的伪随机 u64, FastCDC 滚动指纹计算依赖此表。
+///
+/// 该表是常量数据, 只要保持不变, 同一份字节序列总能切出同样的分块边界,
+/// 这是增量生成和修补两端能够对齐分块的前提。
+const GEAR: [u64; 256] = [
+    0x140d0e39c71c26d2, 0x069100c73a8bad1f, 0xdbe24caca9f3a435, 0x6377ba3266f82afe,
+    0x91d7f9e905853262, 0x4a6a150d6cc7aeb8, 0xa655a7621080aee5, 0xbb76d26629cbab06,
+    0x090c9b36373226bb, 0x074cd796d5bf41d0, 0x4d4d3fc2aa9409cb, 0x6990aa2a968b429d,
+    0xa8cab71cb6c6f806, 0x636f51b780216c5f, 0x07a5144645e30d67, 0x13f22cd734326810,
+    0x806c1468661f06c0, 0xfa51c63fedded956, 0xf7a112dc7878c3f3, 0xd7ef970c55758aaf,
+    0x7270221bca7d9c96, 0x99c7c974513f5c35, 0x2f377fe27fe9d739, 0x83235c58e0055844,
+    0x96814815f7e0cb07, 0x2704c3549f5616d9, 0x20ecb0be99879f66, 0x972ce6c727f2e87d,
+    0xd45d04ddfd1c3a7c, 0x81fe9c0566826c92, 0xa3651273674a3687, 0x9ffbc55422d27243,
+    0xc805ad622d8745ed, 0xba0d89a4f47348f0, 0xefcf65013b652dcf, 0xcb3a9a32e69e7997,
+    0xf97da6b2e7e8f7be, 0x5939c2bb3a4d7f94, 0xcb1158467158e025, 0x4741fd2ea562afbf,
+    0xcbe490643d06bd99, 0x36a4c598dfcfdd5b, 0x5f53a54cdd7391ee, 0xb4d6cd7cc3b11587,
+    0xd435f639e6114ea6, 0x24402121cbc685d2, 0x018da3bed59664f4, 0xfb6f4ccfbd1bf343,
+    0x2d19f936287f3985, 0x14759ecf9da9ce8d, 0x969f66000aefdb1f, 0x5bf2e36d30829b7f,
+    0x9c51c9caf1b65a7f, 0xfb90c6bbfefbec55, 0x9392fc53ad8ead5b, 0xe7276103ec01976d,
+    0x9e6b7d9444e50e67, 0xdfcdb022149e8732, 0x489d618d855dc228, 0x7a63b010fee68db5,
+    0xa7de597b29ec806b, 0x6f89fbde6ff87c9d, 0x37916b94258df35e, 0xe0b5afee3ce7d947,
+    0x067ef86fa875b757, 0x784e8318d26c0f1f, 0x2ecfb432035579ef, 0xc7ff2bc0a0fc14ed,
+    0x35bb5603defa9195, 0xe6f64c29749b1ab0, 0x38347fe30eea74f6, 0xbed18b0598be9f31,
+    0xec9ec5e67e12dee7, 0x9d2436dd2443e6c2, 0x2cf7ca174828a176, 0xdf1a21237132258f,
+    0xa6916dee4076762e, 0x094f0f2fa0acc40d, 0x6ab012f4eaf85a6a, 0x8677c82481a2f7d3,
+    0xc073abb985fe353d, 0x458631f09c911f3a, 0x7b9a4e157bfcdd34, 0x7d4fd8dba557467c,
+    0x9a6daca921b1585f, 0x5032ba4ec0fa1937, 0x622e81dc45aedad7, 0xc13915c723f2ee2a,
+    0x06a2513233c9fd8d, 0xd30649493a31c5d0, 0x80a4ffc904a44c45, 0x00392ea8a4a5274a,
+    0xa34abd8ad9d60417, 0xb28106eccbe8f4ad, 0xc700a2b3bd9798eb, 0xd03c142c62c4127c,
+    0x4a6c7eb2344522c4, 0x5cec9e3a44beda0d, 0x5dfed77a0e731b2a, 0x7c142370aee19b6d,
+    0xe0072573ce8e00d2, 0xca8acee72dd4e2a0, 0x5c49859eabef5756, 0x55f5f3e5ba0c047a,
+    0x01b05cbae2762371, 0xc79c6929392be604, 0xdb3d54527b3912c5, 0xa774e0d8def27659,
+    0x050e6558134686ed, 0xef440f1e5449adaa, 0x6c3b5d7ad0cf5280, 0x5a619ecd19a1c780,
+    0xd42bcfb8929e1427, 0xd0f3ce658bb90ae5, 0x83b486adac1f7d57, 0x0f51b4f6aad6480d,
+    0x06ae19191303be16, 0xcaf600a61e5b05c2, 0xe10e992b1de9ae51, 0xc231fbc769d82b4b,
+    0xca42d83d087794fe, 0x7c25bfebd23a73e2, 0xb987a70b7d25974b, 0xbd37cc42bf5cc54b,
+    0xce06a2c04222b57f, 0x7ed5f23ba9aba456, 0x19362fad2a3e4cfe, 0x5ca50c766138cd28,
+    0x8dd541a0909ddb27, 0x52cbcb254ad0ba97, 0xa890f48229d53da5, 0x25b0e4dd0af54e76,
+    0x567ee206d0acf6e3, 0xf72a9cfd1062df38, 0xa16232c8fb3af1d0, 0xf0edd715e780cce9,
+    0x795c54d6e048468f, 0x31e7758c76691815, 0xc32497c69e7d2cc1, 0x6b21d13cc84bc72e,
+    0x2622020cc73a7ba7, 0x10bd1f4703f048b6, 0xd5211e8c1299920c, 0xa2a1b84e69ef06b8,
+    0x9390a24d07a49c84, 0xd8655baab665c17d, 0x30df18b5d9aa6255, 0xbd0b573a29f210e6,
+    0x7c3390f6cabb855d, 0x02f72452a449fdf0, 0x3430b6b59795400a, 0xee382f4fb6f281b2,
+    0x83a04820b8ea0244, 0x112d949ccd7c4452, 0x8cbd8539815f7432, 0xa1ee210f38cb0736,
+    0x0a4e0c9252d9fea4, 0x51481781fc387756, 0x9ea59ee94c7512d1, 0xa4b4a967fdc44301,
+    0xcba227384e93f7c6, 0xd58088955b608280, 0x40af5305128cc60f, 0x07e07eb4a18ba826,
+    0x038eb17e436ee942, 0x1647d62321f38564, 0x4894712548b4695a, 0x1f587ac3847459cd,
+    0x37fb9b1277090a4f, 0x43a917a34e936a55, 0xed5492c468d942a0, 0x17379df9d4dfd024,
+    0x1902f812d2ba9e2d, 0xa912b789198a8ec8, 0x3fe39f1d5b330ba1, 0x6412635ef63ef9db,
+    0x39b4aebaabcbf0af, 0x8c0e130f2f9f8f24, 0xcda185ceea96580f, 0xe3853fb52a5e5a72,
+    0x191aecb2f1d83b8c, 0x3305f2bde4e9f44e, 0xd037d95a5adbee34, 0xe96a6911b24da1fa,
+    0x2c14b54ea1e52393, 0x2ca0d51640981c24, 0xb28390ad73d560ec, 0x2b200d55c052c183,
+    0x3294a0bb6948054e, 0x3fc0b117b29b7788, 0x940519dae7848136, 0xc7bcbce5b6fb846b,
+    0x30447c235c720040, 0xd0146915bb3245f7, 0xc744770b2099fab1, 0x4b39e7ffb6324583,
+    0xdd3c808dc26ae728, 0x6d765e8dc194224a, 0x6ea0aee9b93e4826, 0x0e3d69841a0ef1a8,
+    0x27ae4f3082047c69, 0xb2bc66c2b4dc1e8d, 0x568328da520aaa33, 0xe54d8d4a78d54d7b,
+    0x1eab1508e2b481eb, 0x898592fad4b41adc, 0xd4c36586c19abffa, 0x88b8dc4a3f7a6c31,
+    0x9db6a92fdbd17018, 0x42fda85eb776d984, 0x0b5ef444d1d628e5, 0xc3aad5ed11048608,
+    0xbdac17aa0320701c, 0x9a21840be6c06e46, 0xdbbd5cac460ae38f, 0x5ee08641106f1bbc,
+    0x9cc4f7e62ac97117, 0xa7dd1ad876237af3, 0xd9dab4b6cc705223, 0x4b14e03bf7d75f98,
+    0x8cdc2f16fa6e5818, 0x36a4026f44995594, 0x8b22206922972e4c, 0xa312e9f782308cee,
+    0xc117562c963d5f8e, 0x76f448c94b2ae29a, 0x5d88a0d3c7d3c4ed, 0x1c1005716dbb28b9,
+    0xc4898172f1ca5807, 0xe91488cab5eede02, 0x44c1fbc8d7e72cb5, 0x21e24684c652d5db,
+    0x427c7892873e6925, 0xf85e7ab0c229f08f, 0xacd48c52fc554373, 0x4c18e39235f352a8,
+    0xbb633612e6571a45, 0xda30fced14b8a3f1, 0xf5fd5f946922284a, 0x0b0a01f20b100f3e,
+    0x2abd3106ab9065b0, 0x39e1dbe5a38fb118, 0x5f476f9688fad3f6, 0xc9692696624e8206,
+    0xbf6b5f8fd9613cf5, 0x0940c0683ebd5ba8, 0x174c598da7558182, 0xf61bd4cdf3ec5ea2,
+    0x06a4b8a0411c9fb6, 0xc7b5bdd9828ae43d, 0x175492d859d16fd3, 0xe7fcf5eaee39b379,
+    0xa508397f370b8c6d, 0x51f9037d9a9164f4, 0x18f247104ab1f14e, 0xe8be1697fb7ea63b,
+];
+
+/// 分块参数: 最小/标准/最大尺寸, 以及归一化分块使用的两档 mask。
+const MIN_SIZE: usize = 2 * 1024;
+const NORMAL_SIZE: usize = 8 * 1024;
+const MAX_SIZE: usize = 64 * 1024;
+/// 归一化分块中, 达到标准尺寸前使用的更严格 mask (置位更多, 切点更少)。
+const MASK_S: u64 = 0x0003_5900_3590_0000;
+/// 越过标准尺寸后使用的更宽松 mask (置位更少, 切点更多)。
+const MASK_L: u64 = 0x0000_d903_5900_0000;
+
+/// 一个分块在 layer 原始字节流中的位置及其内容哈希。
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub offset: u64,
+    pub len: u32,
+    pub hash: String,
+}
+
+/// 使用 FastCDC 对解压后的 layer 字节流做归一化内容定义分块, 返回按偏移升序排列的分块列表。
+pub fn fastcdc(data: &[u8]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+    let len = data.len();
+
+    while start < len {
+        let remaining = len - start;
+        if remaining <= MIN_SIZE {
+            chunks.push(make_chunk(data, start, remaining));
+            break;
+        }
+
+        let mut fp: u64 = 0;
+        let normal_size = NORMAL_SIZE.min(remaining);
+        let hard_max = MAX_SIZE.min(remaining);
+
+        let mut cut = hard_max;
+        let mut i = MIN_SIZE;
+        while i < hard_max {
+            fp = (fp << 1).wrapping_add(GEAR[data[start + i] as usize]);
+            let mask = if i < normal_size { MASK_S } else { MASK_L };
+            if fp & mask == 0 {
+                cut = i;
+                break;
+            }
+            i += 1;
+        }
+
+        chunks.push(make_chunk(data, start, cut));
+        start += cut;
+    }
+
+    chunks
+}
+
+fn make_chunk(data: &[u8], offset: usize, len: usize) -> Chunk {
+    let slice = &data[offset..offset + len];
+    let hash = format!("{:x}", Sha256::digest(slice));
+    Chunk {
+        offset: offset as u64,
+        len: len as u32,
+        hash,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// 构造一段可重复的伪随机数据, 足够长以跨越多个分块边界。
+    fn sample_data(len: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len);
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.extend_from_slice(&state.to_le_bytes());
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn fastcdc_is_deterministic() {
+        let data = sample_data(512 * 1024);
+        let a = fastcdc(&data);
+        let b = fastcdc(&data);
+        let offsets_a: Vec<u64> = a.iter().map(|c| c.offset).collect();
+        let offsets_b: Vec<u64> = b.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets_a, offsets_b);
+        assert!(a.len() > 1, "样本数据应当被切出不止一个分块");
+    }
+
+    #[test]
+    fn fastcdc_respects_size_bounds() {
+        let data = sample_data(512 * 1024);
+        let chunks = fastcdc(&data);
+        for (i, c) in chunks.iter().enumerate() {
+            let is_last = i == chunks.len() - 1;
+            assert!(c.len as usize <= MAX_SIZE, "分块不应超过硬上限");
+            if !is_last {
+                assert!(c.len as usize >= MIN_SIZE, "非末尾分块不应小于最小尺寸");
+            }
+        }
+    }
+
+    #[test]
+    fn chunks_concat_back_to_original() {
+        let data = sample_data(300 * 1024);
+        let chunks = fastcdc(&data);
+
+        let mut rebuilt = Vec::with_capacity(data.len());
+        for c in &chunks {
+            rebuilt.extend_from_slice(&data[c.offset as usize..(c.offset + c.len as u64) as usize]);
+        }
+
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn identical_content_produces_identical_chunk_hashes() {
+        let data = sample_data(200 * 1024);
+        let mut other = sample_data(64 * 1024);
+        other.extend_from_slice(&data);
+
+        let chunks_data = fastcdc(&data);
+        let chunks_other = fastcdc(&other);
+
+        let hashes_data: HashSet<&str> = chunks_data.iter().map(|c| c.hash.as_str()).collect();
+        let hashes_other: HashSet<&str> = chunks_other.iter().map(|c| c.hash.as_str()).collect();
+        assert!(
+            hashes_data.iter().any(|h| hashes_other.contains(h)),
+            "相同内容应当切出至少一个哈希相同的分块"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! 本地层内容库索引
+//!
+//! 记录当前机器已经拥有哪些 layer blob (按 sha256 摘要), 替代 `delta` 原先
+//! 依赖人工从目标机器导出 `docker inspect` 的方式: `index scan` 直接扫描本地
+//! 的 `blobs/sha256/` 目录或已有的镜像 tarball 来建立/更新索引, `delta` 可以
+//! 直接查询这份索引来计算可共享层, `index export`/`index import` 用于把某台
+//! 目标机器的库存快照搬到别处。
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use sled::Db;
+
+use crate::get_layer_digests;
+
+/// 持久化的层摘要索引, 底层用 sled 存储, 键为层摘要 (不带 `sha256:` 前缀)。
+pub struct LayerIndex {
+    db: Db,
+}
+
+impl LayerIndex {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self { db: sled::open(path)? })
+    }
+
+    pub fn record(&self, digest: &str) -> Result<()> {
+        self.db.insert(digest.as_bytes(), &[])?;
+        Ok(())
+    }
+
+    pub fn all_digests(&self) -> Result<Vec<String>> {
+        let mut digests = Vec::new();
+        for item in self.db.iter() {
+            let (key, _) = item?;
+            digests.push(String::from_utf8_lossy(&key).to_string());
+        }
+        Ok(digests)
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+}
+
+/// 扫描一个路径: 若是目录则当作本地 `blobs/sha256/` 目录, 把其中每个文件名
+/// 当作层摘要记录进索引; 若是文件则当作镜像 tarball, 记录其 (所选平台)
+/// manifest 中列出的每一层。返回新记录的层数量。
+pub fn scan_path(index: &LayerIndex, path: &Path, platform: Option<&str>) -> Result<usize> {
+    if path.is_dir() {
+        let mut count = 0;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                if let Some(digest) = entry.file_name().to_str() {
+                    index.record(digest)?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    } else {
+        let layers = get_layer_digests(path, platform)?;
+        for digest in &layers {
+            index.record(digest)?;
+        }
+        Ok(layers.len())
+    }
+}
+
+/// 导出索引为一份纯文本摘要列表 (每行一个 sha256 摘要), 便于传输给其他机器导入。
+pub fn export_to_file(index: &LayerIndex, out_path: &Path) -> Result<usize> {
+    let digests = index.all_digests()?;
+    fs::write(out_path, digests.join("\n"))?;
+    Ok(digests.len())
+}
+
+/// 从导出的摘要列表文件导入层记录到索引。
+pub fn import_from_file(index: &LayerIndex, in_path: &Path) -> Result<usize> {
+    let content = fs::read_to_string(in_path)?;
+    let mut count = 0;
+    for line in content.lines() {
+        let digest = line.trim();
+        if !digest.is_empty() {
+            index.record(digest)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("docker-image-patcher-index-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn record_and_all_digests_round_trip() {
+        let path = temp_path("scan");
+        let idx = LayerIndex::open(&path).expect("打开索引失败");
+        idx.record("aaa").unwrap();
+        idx.record("bbb").unwrap();
+        idx.record("aaa").unwrap();
+
+        let mut digests = idx.all_digests().unwrap();
+        digests.sort();
+        assert_eq!(digests, vec!["aaa".to_string(), "bbb".to_string()]);
+        assert_eq!(idx.len(), 2);
+
+        fs::remove_dir_all(&path).ok();
+    }
+
+    #[test]
+    fn export_then_import_preserves_digests() {
+        let index_path = temp_path("export-src");
+        let idx = LayerIndex::open(&index_path).expect("打开索引失败");
+        idx.record("ccc").unwrap();
+        idx.record("ddd").unwrap();
+
+        let out_path = temp_path("export-out.txt");
+        let exported = export_to_file(&idx, &out_path).unwrap();
+        assert_eq!(exported, 2);
+
+        let other_index_path = temp_path("import-dst");
+        let other_idx = LayerIndex::open(&other_index_path).expect("打开索引失败");
+        let imported = import_from_file(&other_idx, &out_path).unwrap();
+        assert_eq!(imported, 2);
+
+        let mut digests = other_idx.all_digests().unwrap();
+        digests.sort();
+        assert_eq!(digests, vec!["ccc".to_string(), "ddd".to_string()]);
+
+        fs::remove_dir_all(&index_path).ok();
+        fs::remove_dir_all(&other_index_path).ok();
+        fs::remove_file(&out_path).ok();
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File};
 use std::io::{Read, Write};
@@ -6,12 +6,24 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{Result};
 use clap::Parser;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use log::{debug, error, info, warn};
-use serde::{Deserialize};
+use serde::{Deserialize, Serialize};
 use tar::{Archive, Builder};
 
+mod chunk;
+mod index;
+mod oci;
+mod rebuild;
+mod verify;
+mod zstd_patch;
+
 #[derive(Debug, Deserialize)]
 struct ManifestEntry {
+    #[serde(rename = "Config")]
+    config: String,
     #[serde(rename = "Layers")]
     layers: Vec<String>,
 }
@@ -32,14 +44,53 @@ struct RootFS {
     layers: Vec<String>,
 }
 
+/// 一个变更层的分块清单, 记录按偏移排列的分块及其哈希, 用于在 `patch()` 中
+/// 按序拼接分块来重建该层的解压字节流。`raw_digest` 是生成增量文件时对解压
+/// 后原始字节算出的 sha256, 用于校验重建结果, 而不是校验重新 gzip 后的压缩
+/// 字节 —— 本地 gzip 编码器几乎不可能与产出原镜像的 gzip 实现逐字节一致,
+/// 按压缩产物校验只会对每个重建层都误报失败。
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkManifest {
+    layer_digest: String,
+    raw_digest: String,
+    chunks: Vec<ChunkEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkEntry {
+    offset: u64,
+    len: u32,
+    hash: String,
+}
+
+/// 某个变更层改用 zstd patch-from 差分时的元信息, 记录配对的旧层摘要以及
+/// 还原所需的解压后长度, 用于在 `patch()` 中定位旧层并还原出新层内容。
+/// `raw_digest` 同 [`ChunkManifest::raw_digest`], 是解压后原始字节的 sha256。
+#[derive(Debug, Serialize, Deserialize)]
+struct ZstdPatchMeta {
+    layer_digest: String,
+    base_digest: String,
+    new_len: usize,
+    raw_digest: String,
+}
+
 #[derive(Parser)]
 enum Cli {
-    /// 根据目标机器上旧镜像的 inspect 信息, 创建指定镜像 tarball 的增量文件
+    /// 根据目标机器上旧镜像的层信息 (inspect 导出或本地索引), 创建指定镜像 tarball 的增量文件
     Delta {
         /// 指定镜像 tarball 路径
         tar_path: PathBuf,
-        /// 旧镜像的 inspect 信息路径
-        inspect_path: PathBuf,
+        /// 旧镜像的 inspect 信息路径; 与 `--index` 至少提供一个
+        inspect_path: Option<PathBuf>,
+        /// 本地层索引路径, 直接查询目标机器实际已有的层, 替代手工导出的 inspect 文件
+        #[arg(long)]
+        index: Option<PathBuf>,
+        /// 旧镜像 tarball 路径 (如果本地已有, 可借助 zstd patch-from 生成更小的二进制差分)
+        #[arg(long = "old-tar")]
+        old_tar_path: Option<PathBuf>,
+        /// 当镜像 tarball 为 OCI image layout 且包含多个平台时, 指定要处理的平台 (如 linux/amd64)
+        #[arg(long)]
+        platform: Option<String>,
     },
     /// 基于旧镜像 tarball, 使用增量文件进行修补
     Patch {
@@ -47,10 +98,90 @@ enum Cli {
         tar_path: PathBuf,
         /// 增量文件路径
         delta_path: PathBuf,
+        /// 当镜像 tarball 为 OCI image layout 且包含多个平台时, 指定要处理的平台 (如 linux/amd64)
+        #[arg(long)]
+        platform: Option<String>,
+        /// 跳过对复用 blob 及重建结果的 sha256 完整性校验 (默认开启校验)
+        #[arg(long = "no-verify", action = clap::ArgAction::SetFalse, default_value_t = true)]
+        verify: bool,
+    },
+    /// 维护本地层内容库索引
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
     },
 }
 
-fn get_manifest_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
+#[derive(clap::Subcommand)]
+enum IndexCommand {
+    /// 扫描本地 blobs/sha256/ 目录或镜像 tarball, 将其中的层摘要记录进索引
+    Scan {
+        /// 索引路径 (sled 数据库目录)
+        index_path: PathBuf,
+        /// 要扫描的 blobs/sha256/ 目录或镜像 tarball 路径, 可指定多个
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// 扫描镜像 tarball 时, 若其为 OCI image layout 且包含多个平台, 指定要扫描的平台
+        #[arg(long)]
+        platform: Option<String>,
+    },
+    /// 导出索引中记录的全部层摘要到一个文本文件
+    Export {
+        /// 索引路径 (sled 数据库目录)
+        index_path: PathBuf,
+        /// 导出的摘要列表文件路径
+        out_path: PathBuf,
+    },
+    /// 从导出的摘要列表文件导入层记录到索引
+    Import {
+        /// 索引路径 (sled 数据库目录)
+        index_path: PathBuf,
+        /// 待导入的摘要列表文件路径
+        in_path: PathBuf,
+    },
+}
+
+/// 返回 tarball 中指定 (或唯一) 平台镜像的有序层摘要列表;
+/// 对 OCI image layout 走 `index.json`, 对 Docker `manifest.json` 则沿用旧逻辑。
+fn get_ordered_layers(tar_path: &Path, platform: Option<&str>) -> Result<Vec<String>> {
+    if oci::is_oci_layout(tar_path)? {
+        Ok(oci::resolve_image(tar_path, platform)?.layer_digests)
+    } else {
+        Ok(get_manifest_image_layers(tar_path)?.into_iter().next().unwrap_or_default())
+    }
+}
+
+/// 返回 tarball 中 (所选平台) 镜像涉及的全部层摘要, 不保证顺序, 用于集合运算。
+pub(crate) fn get_layer_digests(tar_path: &Path, platform: Option<&str>) -> Result<Vec<String>> {
+    if oci::is_oci_layout(tar_path)? {
+        Ok(oci::resolve_image(tar_path, platform)?.layer_digests)
+    } else {
+        get_manifest_layers_from_tarball(tar_path)
+    }
+}
+
+/// 返回 tarball 中 (所选平台) 镜像的配置 blob 摘要。
+fn get_config_digest(tar_path: &Path, platform: Option<&str>) -> Result<String> {
+    if oci::is_oci_layout(tar_path)? {
+        Ok(oci::resolve_image(tar_path, platform)?.config_digest)
+    } else {
+        let content = read_entry_bytes(tar_path, "manifest.json")?
+            .ok_or_else(|| anyhow::anyhow!("镜像 tarball 中不存在 manifest.json"))?;
+        let manifest: Vec<ManifestEntry> = serde_json::from_slice(&content)
+            .expect("manifest.json 解析失败");
+        let config = manifest
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("manifest.json 中不存在任何镜像"))?;
+        Ok(Path::new(&config.config)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string())
+    }
+}
+
+/// 按镜像顺序返回 manifest.json 中每个镜像各自的有序层列表 (文件名形式的摘要)。
+fn get_manifest_image_layers(tar_path: &Path) -> Result<Vec<Vec<String>>> {
     let file = File::open(tar_path)?;
     let mut archive = Archive::new(file);
 
@@ -62,23 +193,63 @@ fn get_manifest_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
             let manifest: Vec<ManifestEntry> = serde_json::from_str(&content)
                 .expect("manifest.json 解析失败");
 
-            let mut layers = Vec::new();
-            for image in manifest {
-                for layer in image.layers {
-                    layers.push(Path::new(&layer).file_name()
-                        .unwrap_or_default()
-                        .to_string_lossy()
-                        .to_string());
-                }
-            }
-            return Ok(layers);
+            return Ok(manifest
+                .into_iter()
+                .map(|image| {
+                    image
+                        .layers
+                        .into_iter()
+                        .map(|layer| {
+                            Path::new(&layer)
+                                .file_name()
+                                .unwrap_or_default()
+                                .to_string_lossy()
+                                .to_string()
+                        })
+                        .collect()
+                })
+                .collect());
         }
     }
 
     anyhow::bail!("镜像 tarball 中不存在 manifest.json");
 }
 
-fn get_missing_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
+fn get_manifest_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
+    Ok(get_manifest_image_layers(tar_path)?.into_iter().flatten().collect())
+}
+
+/// 返回增量文件中可以被重建出来的层摘要集合: 要么带有 `chunks/<digest>.manifest.json`
+/// 分块清单, 要么带有 `zstd/<digest>.json` 差分元信息。这些层在增量文件里并不以
+/// `blobs/sha256/<digest>` blob 的形式存在 (压缩后的新摘要与旧 tarball 里任何层的
+/// 摘要都不同), 但并不代表它们"缺失", 只是需要在 `patch()` 里重建出来。
+fn get_reconstructable_layers_from_delta(delta_path: &Path) -> Result<HashSet<String>> {
+    let file = File::open(delta_path)?;
+    let mut archive = Archive::new(file);
+
+    let mut reconstructable = HashSet::new();
+    for entry_result in archive.entries()? {
+        let entry = entry_result?;
+        let path = entry.path()?.to_path_buf();
+        let Some(filename) = path.file_name().map(|f| f.to_string_lossy().to_string()) else {
+            continue;
+        };
+
+        if let Some(digest) = filename.strip_suffix(".manifest.json") {
+            if path.starts_with("chunks") {
+                reconstructable.insert(digest.to_string());
+            }
+        } else if let Some(digest) = filename.strip_suffix(".json") {
+            if path.starts_with("zstd") {
+                reconstructable.insert(digest.to_string());
+            }
+        }
+    }
+
+    Ok(reconstructable)
+}
+
+fn get_missing_layers_from_tarball(tar_path: &Path, platform: Option<&str>) -> Result<Vec<String>> {
     let file = File::open(tar_path)?;
     let mut archive = Archive::new(file);
 
@@ -97,7 +268,7 @@ fn get_missing_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
 
     debug!("blob 层: {:?}", blob_layers);
 
-    let manifest_layers: HashSet<String> = get_manifest_layers_from_tarball(tar_path)?
+    let manifest_layers: HashSet<String> = get_layer_digests(tar_path, platform)?
         .into_iter()
         .collect();
 
@@ -111,6 +282,80 @@ fn get_missing_layers_from_tarball(tar_path: &Path) -> Result<Vec<String>> {
     Ok(missing)
 }
 
+fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = GzDecoder::new(data);
+    let mut raw = Vec::new();
+    decoder.read_to_end(&mut raw)?;
+    Ok(raw)
+}
+
+fn gzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// 在 tarball 中查找指定路径的条目并读出其全部字节, 不存在则返回 `None`。
+pub(crate) fn read_entry_bytes(tar_path: &Path, entry_path: &str) -> Result<Option<Vec<u8>>> {
+    let file = File::open(tar_path)?;
+    let mut archive = Archive::new(file);
+
+    for entry_result in archive.entries()? {
+        let mut entry = entry_result?;
+        if entry.path()? == Path::new(entry_path) {
+            let mut buffer = Vec::new();
+            entry.read_to_end(&mut buffer)?;
+            return Ok(Some(buffer));
+        }
+    }
+
+    Ok(None)
+}
+
+/// 对 tarball 中属于 `layer_digests` 的每个 layer blob 解压并做 FastCDC 分块,
+/// 返回 `分块哈希 -> 分块字节` 的映射, 用作"已知内容"的分块索引。
+fn chunk_index_for_layers(
+    tar_path: &Path,
+    layer_digests: &HashSet<String>,
+    verify: bool,
+) -> Result<HashMap<String, Vec<u8>>> {
+    let file = File::open(tar_path)?;
+    let mut archive = Archive::new(file);
+
+    let mut index = HashMap::new();
+    for entry_result in archive.entries()? {
+        let mut entry = entry_result?;
+        let path = entry.path()?.to_path_buf();
+        let path_str = path.to_string_lossy().to_string();
+        let Some(filename) = path.file_name().map(|f| f.to_string_lossy().to_string()) else {
+            continue;
+        };
+
+        if path_str.starts_with("blobs/sha256/") && layer_digests.contains(&filename) {
+            let mut buffer = Vec::new();
+            entry.read_to_end(&mut buffer)?;
+            if verify {
+                verify::verify_digest(&format!("层 {}", filename), &buffer, &filename)?;
+            }
+            let raw = gunzip(&buffer)?;
+            for c in chunk::fastcdc(&raw) {
+                let bytes = raw[c.offset as usize..(c.offset + c.len as u64) as usize].to_vec();
+                index.entry(c.hash).or_insert(bytes);
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+/// 同 [`chunk_index_for_layers`], 但只返回分块哈希集合, 用于判断某分块内容
+/// 是否已经存在于目标可直接获得的层中 (例如共享层)。
+fn known_chunk_hashes(tar_path: &Path, layer_digests: &HashSet<String>) -> Result<HashSet<String>> {
+    Ok(chunk_index_for_layers(tar_path, layer_digests, false)?
+        .into_keys()
+        .collect())
+}
+
 fn get_layers_from_inspect(inspect_path: &Path) -> Result<Vec<String>> {
     let content = fs::read_to_string(inspect_path)?;
     let inspect: Vec<InspectEntry> = serde_json::from_str(&content)?;
@@ -145,18 +390,33 @@ fn get_layers_from_inspect(inspect_path: &Path) -> Result<Vec<String>> {
     Ok(layers)
 }
 
-fn delta(tar_path: &Path, inspect_path: &Path) -> Result<()> {
-    let tar_layers: HashSet<String> = get_manifest_layers_from_tarball(tar_path)?
+fn delta(
+    tar_path: &Path,
+    inspect_path: Option<&Path>,
+    index_path: Option<&Path>,
+    old_tar_path: Option<&Path>,
+    platform: Option<&str>,
+) -> Result<()> {
+    let tar_layers: HashSet<String> = get_layer_digests(tar_path, platform)?
         .into_iter()
         .collect();
     debug!("tarball 层: {:?}", tar_layers);
     info!("镜像 tarball 中共有 {} 层", tar_layers.len());
 
-    let inspect_layers: HashSet<String> = get_layers_from_inspect(inspect_path)?
-        .into_iter()
-        .collect();
-    debug!("inspect 层: {:?}", inspect_layers);
-    info!("inspect 文件中镜像共有 {} 层", inspect_layers.len());
+    // 目标机器已有的层, 优先直接查询本地索引; 若未提供索引则回退到 inspect 导出文件。
+    let inspect_layers: HashSet<String> = if let Some(index_path) = index_path {
+        let idx = index::LayerIndex::open(index_path)?;
+        let known: HashSet<String> = idx.all_digests()?.into_iter().collect();
+        info!("索引 {} 中共记录 {} 个层", index_path.display(), known.len());
+        known
+    } else if let Some(inspect_path) = inspect_path {
+        let layers: HashSet<String> = get_layers_from_inspect(inspect_path)?.into_iter().collect();
+        info!("inspect 文件中镜像共有 {} 层", layers.len());
+        layers
+    } else {
+        anyhow::bail!("必须提供 inspect 文件或 --index 其中之一");
+    };
+    debug!("目标已有层: {:?}", inspect_layers);
 
     let shared_layers: HashSet<String> = tar_layers
         .intersection(&inspect_layers)
@@ -178,6 +438,33 @@ fn delta(tar_path: &Path, inspect_path: &Path) -> Result<()> {
     let new_file = File::create(&delta_tar)?;
     let mut new_builder = Builder::new(new_file);
 
+    // 共享层的内容目标机器上已经存在, 因此共享层内切出的分块同样视为"已知分块",
+    // 后续变更层的分块只要与之重复就无需再次发送。
+    let mut known_chunks = known_chunk_hashes(tar_path, &shared_layers)?;
+    debug!("已知分块数: {}", known_chunks.len());
+
+    // 若调用方提供了本地旧 tarball, 按层的下标配对新旧层,
+    // 为变更层尝试生成比分块更紧凑的 zstd patch-from 差分。
+    let layer_pairs: HashMap<String, String> = if let Some(old_tar) = old_tar_path {
+        let new_order = get_ordered_layers(tar_path, platform)?;
+        let old_order = get_ordered_layers(old_tar, platform)?;
+        new_order
+            .into_iter()
+            .zip(old_order)
+            .filter(|(new_digest, old_digest)| new_digest != old_digest)
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // OCI image layout 下, 只保留所选平台自身的 manifest/config/层 blob,
+    // 避免把其他平台的内容也打包进增量文件。
+    let keep_blobs: Option<HashSet<String>> = if oci::is_oci_layout(tar_path)? {
+        Some(oci::resolve_image(tar_path, platform)?.owned_digests())
+    } else {
+        None
+    };
+
     info!("开始生成增量文件");
     for entry_result in old_archive.entries()? {
         let mut entry = entry_result?;
@@ -185,11 +472,115 @@ fn delta(tar_path: &Path, inspect_path: &Path) -> Result<()> {
         let path_str = path.to_string_lossy().to_string();
 
         if shared_layers_path.contains(&path_str) {
-            debug!("跳过 {}", path_str);
+            debug!("跳过共享层 {}", path_str);
             continue;
         }
 
-        // 创建新的entry并获取header和path
+        let filename = path.file_name().map(|f| f.to_string_lossy().to_string());
+        let is_changed_layer = path_str.starts_with("blobs/sha256/")
+            && filename.as_ref().is_some_and(|f| tar_layers.contains(f));
+
+        if is_changed_layer {
+            let digest = filename.unwrap();
+            let mut buffer = Vec::new();
+            entry.read_to_end(&mut buffer)?;
+            let raw = gunzip(&buffer)?;
+
+            if let Some(base_digest) = layer_pairs.get(&digest) {
+                if let Some(old_blob) = read_entry_bytes(
+                    old_tar_path.expect("layer_pairs 非空意味着提供了 old_tar_path"),
+                    &format!("blobs/sha256/{}", base_digest),
+                )? {
+                    let old_raw = gunzip(&old_blob)?;
+                    match zstd_patch::diff(&old_raw, &raw) {
+                        Ok(patch_bytes) if patch_bytes.len() < buffer.len() => {
+                            let meta = ZstdPatchMeta {
+                                layer_digest: digest.clone(),
+                                base_digest: base_digest.clone(),
+                                new_len: raw.len(),
+                                raw_digest: verify::sha256_hex(&raw),
+                            };
+                            let meta_json = serde_json::to_vec_pretty(&meta)?;
+                            let meta_path = format!("zstd/{}.json", digest);
+                            let mut meta_header = tar::Header::new_gnu();
+                            meta_header.set_size(meta_json.len() as u64);
+                            meta_header.set_cksum();
+                            new_builder.append_data(&mut meta_header, &meta_path, &meta_json[..])?;
+
+                            let patch_path = format!("zstd/{}.patch", digest);
+                            let mut patch_header = tar::Header::new_gnu();
+                            patch_header.set_size(patch_bytes.len() as u64);
+                            patch_header.set_cksum();
+                            new_builder.append_data(&mut patch_header, &patch_path, &patch_bytes[..])?;
+
+                            info!(
+                                "层 {} 以 {} 为基础生成 zstd 差分, {} -> {} 字节",
+                                digest,
+                                base_digest,
+                                buffer.len(),
+                                patch_bytes.len()
+                            );
+                            continue;
+                        }
+                        Ok(_) => debug!("层 {} 的 zstd 差分未比原始 blob 更小, 改用分块方案", digest),
+                        Err(err) => debug!("层 {} 生成 zstd 差分失败: {}, 改用分块方案", digest, err),
+                    }
+                }
+            }
+
+            let chunks = chunk::fastcdc(&raw);
+
+            let mut manifest_entries = Vec::with_capacity(chunks.len());
+            let mut shipped = 0usize;
+            for c in &chunks {
+                manifest_entries.push(ChunkEntry {
+                    offset: c.offset,
+                    len: c.len,
+                    hash: c.hash.clone(),
+                });
+
+                if known_chunks.insert(c.hash.clone()) {
+                    let bytes = &raw[c.offset as usize..(c.offset + c.len as u64) as usize];
+                    let chunk_path = format!("chunks/{}", c.hash);
+                    let mut chunk_header = tar::Header::new_gnu();
+                    chunk_header.set_size(bytes.len() as u64);
+                    chunk_header.set_cksum();
+                    new_builder.append_data(&mut chunk_header, &chunk_path, bytes)?;
+                    shipped += 1;
+                }
+            }
+
+            let manifest = ChunkManifest {
+                layer_digest: digest.clone(),
+                raw_digest: verify::sha256_hex(&raw),
+                chunks: manifest_entries,
+            };
+            let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+            let manifest_path = format!("chunks/{}.manifest.json", digest);
+            let mut manifest_header = tar::Header::new_gnu();
+            manifest_header.set_size(manifest_json.len() as u64);
+            manifest_header.set_cksum();
+            new_builder.append_data(&mut manifest_header, &manifest_path, &manifest_json[..])?;
+
+            info!(
+                "层 {} 切分为 {} 个分块, 其中 {} 个为新增分块",
+                digest,
+                chunks.len(),
+                shipped
+            );
+            continue;
+        }
+
+        if path_str.starts_with("blobs/sha256/") {
+            if let Some(keep) = &keep_blobs {
+                if filename.as_ref().is_some_and(|f| !keep.contains(f)) {
+                    debug!("跳过非目标平台 blob {}", path_str);
+                    continue;
+                }
+            }
+        }
+
+        // 其余条目 (manifest.json/index.json、镜像配置、repositories 等) 原样拷贝
         let mut buffer = Vec::new();
         entry.read_to_end(&mut buffer)?;
         let mut new_entry = tar::Header::new_gnu();
@@ -204,21 +595,26 @@ fn delta(tar_path: &Path, inspect_path: &Path) -> Result<()> {
     Ok(())
 }
 
-fn patch(tar_path: &Path, delta_path: &Path) -> Result<()> {
-    let tar_layers: HashSet<String> = get_manifest_layers_from_tarball(tar_path)?
+fn patch(tar_path: &Path, delta_path: &Path, platform: Option<&str>, verify: bool) -> Result<()> {
+    let tar_layers: HashSet<String> = get_layer_digests(tar_path, platform)?
         .into_iter()
         .collect();
     debug!("tarball 层: {:?}", tar_layers);
     info!("镜像 tarball 中共有 {} 层", tar_layers.len());
 
-    let missing_layers: HashSet<String> = get_missing_layers_from_tarball(delta_path)?
+    let missing_layers: HashSet<String> = get_missing_layers_from_tarball(delta_path, platform)?
         .into_iter()
         .collect();
     debug!("增量文件中缺少层: {:?}", missing_layers);
     info!("增量文件中缺少 {} 层", missing_layers.len());
 
+    // "缺失" 只代表该层没有以 blob 形式出现在增量文件里: 未变化的共享层应该
+    // 能在旧 tarball 里原样找到, 而变化过的层则带有 chunks/、zstd/ 重建信息,
+    // 其新摘要本就不会出现在旧 tarball 的层集合中, 不能当作"找不到"处理。
+    let reconstructable = get_reconstructable_layers_from_delta(delta_path)?;
     let layer_not_found: HashSet<String> = missing_layers
         .difference(&tar_layers)
+        .filter(|digest| !reconstructable.contains(*digest))
         .cloned()
         .collect();
 
@@ -231,41 +627,157 @@ fn patch(tar_path: &Path, delta_path: &Path) -> Result<()> {
     info!("开始修补镜像 tarball");
     let new_tar_path = delta_path.with_extension("tar");
 
-    // 复制delta文件到新tarball
-    fs::copy(delta_path, &new_tar_path)?;
+    // 对旧 tarball 做一次顺序扫描, 建立 blob 偏移索引; 重建缺失层时直接按偏移
+    // seek 读取, 不必为每一层都重新打开旧归档做一次全量线性扫描。
+    let old_blob_index = rebuild::index_old_blobs(tar_path)?;
+
+    // chunks/、zstd/ 两个前缀下的内容只是增量文件内部的传输数据, 不应出现在
+    // 最终产出的镜像里; 这里先数一遍会被原样拷贝的条目数, 连同待重建的层数
+    // 一起作为进度条总量。
+    let delta_entry_count = {
+        let file = File::open(delta_path)?;
+        let mut archive = Archive::new(file);
+        let mut count = 0u64;
+        for entry_result in archive.entries()? {
+            let entry = entry_result?;
+            let path_str = entry.path()?.to_string_lossy().to_string();
+            if !(path_str.starts_with("chunks/") || path_str.starts_with("zstd/")) {
+                count += 1;
+            }
+        }
+        count
+    };
+    let bar = rebuild::progress_bar(delta_entry_count + missing_layers.len() as u64);
+
+    let new_file = File::create(&new_tar_path)?;
+    let mut new_builder = Builder::new(new_file);
+    let mut bytes_written: u64 = 0;
+
+    // 第一遍: 把增量文件里除 chunks/、zstd/ 辅助条目外的内容流式拷贝过去,
+    // 统一经 tar::Builder 写出规范的 GNU 头, 而不是像旧实现那样整份 `fs::copy`
+    // 再在结尾附加裸字节。
+    let delta_file = File::open(delta_path)?;
+    let mut delta_archive = Archive::new(delta_file);
+    for entry_result in delta_archive.entries()? {
+        let mut entry = entry_result?;
+        let path = entry.path()?.to_path_buf();
+        let path_str = path.to_string_lossy().to_string();
+        if path_str.starts_with("chunks/") || path_str.starts_with("zstd/") {
+            continue;
+        }
+
+        let mut header = entry.header().clone();
+        let size = header.size()?;
+        new_builder.append_data(&mut header, &path, &mut entry)?;
+        bytes_written += size;
+        bar.inc(1);
+        bar.set_message(format!("已写入 {} 字节", bytes_written));
+    }
 
-    // 以追加模式打开新文件
-    let mut new_file = fs::OpenOptions::new()
-        .append(true)
-        .open(&new_tar_path)?;
+    // 旧 tarball 各层的分块索引, 仅在确实遇到需要按分块重建的层时才计算
+    let mut old_chunk_index: Option<HashMap<String, Vec<u8>>> = None;
 
-    for missing_layer in missing_layers {
-        debug!("添加 {}", missing_layer);
+    for missing_layer in &missing_layers {
         let layer_path = format!("blobs/sha256/{}", missing_layer);
 
-        // 重新打开旧文件以查找特定条目
-        let old_file = File::open(tar_path)?;
-        let mut old_archive = Archive::new(old_file);
+        let zstd_meta_path = format!("zstd/{}.json", missing_layer);
+        let compressed = if let Some(meta_bytes) = read_entry_bytes(delta_path, &zstd_meta_path)? {
+            debug!("按 zstd 差分重建层 {}", missing_layer);
+            let meta: ZstdPatchMeta = serde_json::from_slice(&meta_bytes)?;
 
-        let mut found = false;
-        for entry_result in old_archive.entries()? {
-            let mut entry = entry_result?;
-            let entry_path = entry.path()?;
+            let old_blob = rebuild::read_indexed_blob(tar_path, &old_blob_index, &meta.base_digest)?
+                .ok_or_else(|| anyhow::anyhow!("旧 tarball 中未找到基础层: {}", meta.base_digest))?;
+            if verify {
+                verify::verify_digest(&format!("基础层 {}", meta.base_digest), &old_blob, &meta.base_digest)?;
+            }
+            let old_raw = gunzip(&old_blob)?;
 
-            if entry_path == Path::new(&layer_path) {
-                let mut buffer = Vec::new();
-                entry.read_to_end(&mut buffer)?;
+            let patch_bytes = read_entry_bytes(delta_path, &format!("zstd/{}.patch", missing_layer))?
+                .ok_or_else(|| anyhow::anyhow!("增量文件中未找到 zstd 差分: {}", missing_layer))?;
 
-                // 将找到的条目添加到新文件
-                new_file.write_all(&buffer)?;
-                found = true;
-                break;
+            let raw = zstd_patch::apply(&old_raw, &patch_bytes, meta.new_len)?;
+            if verify {
+                verify::verify_digest(&format!("层 {} 的重建内容", missing_layer), &raw, &meta.raw_digest)?;
+            }
+            info!("层 {} 由 zstd 差分 (基于 {}) 重建完成", missing_layer, meta.base_digest);
+            gzip(&raw)?
+        } else {
+            let manifest_path = format!("chunks/{}.manifest.json", missing_layer);
+            if let Some(manifest_bytes) = read_entry_bytes(delta_path, &manifest_path)? {
+                debug!("按分块重建层 {}", missing_layer);
+                let manifest: ChunkManifest = serde_json::from_slice(&manifest_bytes)?;
+
+                if old_chunk_index.is_none() {
+                    old_chunk_index = Some(chunk_index_for_layers(tar_path, &tar_layers, verify)?);
+                }
+                let index = old_chunk_index.as_ref().unwrap();
+
+                let mut raw = Vec::with_capacity(manifest.chunks.iter().map(|c| c.len as usize).sum());
+                for c in &manifest.chunks {
+                    if let Some(bytes) = read_entry_bytes(delta_path, &format!("chunks/{}", c.hash))? {
+                        raw.extend_from_slice(&bytes);
+                    } else if let Some(bytes) = index.get(&c.hash) {
+                        raw.extend_from_slice(bytes);
+                    } else {
+                        anyhow::bail!("分块 {} 在增量文件和旧 tarball 中均未找到", c.hash);
+                    }
+                }
+
+                if verify {
+                    verify::verify_digest(&format!("层 {} 的重建内容", missing_layer), &raw, &manifest.raw_digest)?;
+                }
+                info!("层 {} 由 {} 个分块重建完成", missing_layer, manifest.chunks.len());
+                gzip(&raw)?
+            } else {
+                debug!("从旧 tarball 直接复用层 {}", missing_layer);
+                let buffer = rebuild::read_indexed_blob(tar_path, &old_blob_index, missing_layer)?
+                    .ok_or_else(|| anyhow::anyhow!("在旧tarball中未找到层: {}", layer_path))?;
+                if verify {
+                    verify::verify_digest(&format!("层 {}", missing_layer), &buffer, missing_layer)?;
+                }
+                buffer
+            }
+        };
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(compressed.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        new_builder.append_data(&mut header, &layer_path, &compressed[..])?;
+        bytes_written += compressed.len() as u64;
+
+        bar.inc(1);
+        bar.set_message(format!("已写入 {} 字节", bytes_written));
+    }
+
+    bar.finish_with_message(format!("修补完成, 共写入 {} 字节", bytes_written));
+    new_builder.finish()?;
+
+    if verify {
+        info!("开始校验修补后的镜像");
+
+        let config_digest = get_config_digest(&new_tar_path, platform)?;
+        let config_bytes = read_entry_bytes(&new_tar_path, &format!("blobs/sha256/{}", config_digest))?
+            .ok_or_else(|| anyhow::anyhow!("修补后的镜像中缺少配置 blob: {}", config_digest))?;
+        verify::verify_digest("配置", &config_bytes, &config_digest)?;
+
+        for layer in get_ordered_layers(&new_tar_path, platform)? {
+            let layer_bytes = read_entry_bytes(&new_tar_path, &format!("blobs/sha256/{}", layer))?
+                .ok_or_else(|| anyhow::anyhow!("修补后的镜像中缺少层: {}", layer))?;
+
+            if reconstructable.contains(&layer) {
+                // 该层由分块/zstd 差分重建 (而非从旧 tarball 原样复用), 本地 gzip
+                // 重新压缩的字节不会与原镜像的压缩 blob 逐字节一致; 其解压内容
+                // 已经在重建时对照 raw_digest 校验过, 这里不再对压缩 blob 做会
+                // 必然失败的摘要比对。
+                info!("层 {} 为重建层, 解压内容已在重建时校验, 跳过压缩 blob 校验", layer);
+                continue;
             }
-        }
 
-        if !found {
-            anyhow::bail!("在旧tarball中未找到层: {}", layer_path);
+            verify::verify_digest(&format!("层 {}", layer), &layer_bytes, &layer)?;
         }
+
+        info!("镜像校验通过");
     }
 
     info!("镜像 tarball 修补完毕，保存在 {}", new_tar_path.display());
@@ -277,7 +789,152 @@ fn main() -> Result<()> {
     env_logger::init();
 
     match Cli::parse() {
-        Cli::Delta { tar_path, inspect_path } => delta(&tar_path, &inspect_path),
-        Cli::Patch { tar_path, delta_path } => patch(&tar_path, &delta_path),
+        Cli::Delta { tar_path, inspect_path, index, old_tar_path, platform } => delta(
+            &tar_path,
+            inspect_path.as_deref(),
+            index.as_deref(),
+            old_tar_path.as_deref(),
+            platform.as_deref(),
+        ),
+        Cli::Patch { tar_path, delta_path, platform, verify } => {
+            patch(&tar_path, &delta_path, platform.as_deref(), verify)
+        }
+        Cli::Index { command } => run_index_command(command),
+    }
+}
+
+fn run_index_command(command: IndexCommand) -> Result<()> {
+    match command {
+        IndexCommand::Scan { index_path, paths, platform } => {
+            let idx = index::LayerIndex::open(&index_path)?;
+            let mut total = 0;
+            for path in &paths {
+                let count = index::scan_path(&idx, path, platform.as_deref())?;
+                info!("从 {} 中记录了 {} 个层", path.display(), count);
+                total += count;
+            }
+            info!("扫描完成, 共记录 {} 个层, 索引现有 {} 个层", total, idx.len());
+            Ok(())
+        }
+        IndexCommand::Export { index_path, out_path } => {
+            let idx = index::LayerIndex::open(&index_path)?;
+            let count = index::export_to_file(&idx, &out_path)?;
+            info!("导出了 {} 个层摘要到 {}", count, out_path.display());
+            Ok(())
+        }
+        IndexCommand::Import { index_path, in_path } => {
+            let idx = index::LayerIndex::open(&index_path)?;
+            let count = index::import_from_file(&idx, &in_path)?;
+            info!("从 {} 导入了 {} 个层摘要", in_path.display(), count);
+            Ok(())
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// 构造一份最简 manifest.json 风格镜像 tarball: 每层、配置都以
+    /// `blobs/sha256/<digest>` 形式存放 (新版 `docker save` 的产出格式),
+    /// `layer_raw_contents` 为各层解压后的原始字节。返回各层的 gzip 压缩摘要。
+    fn write_test_image(path: &Path, layer_raw_contents: &[Vec<u8>]) -> Vec<String> {
+        let file = File::create(path).unwrap();
+        let mut builder = Builder::new(file);
+
+        let mut layer_digests = Vec::new();
+        for raw in layer_raw_contents {
+            let compressed = gzip(raw).unwrap();
+            let digest = verify::sha256_hex(&compressed);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(compressed.len() as u64);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("blobs/sha256/{}", digest), &compressed[..])
+                .unwrap();
+            layer_digests.push(digest);
+        }
+
+        let config = b"{\"rootfs\":{\"type\":\"layers\",\"diff_ids\":[]}}".to_vec();
+        let config_digest = verify::sha256_hex(&config);
+        let mut config_header = tar::Header::new_gnu();
+        config_header.set_size(config.len() as u64);
+        config_header.set_cksum();
+        builder
+            .append_data(&mut config_header, format!("blobs/sha256/{}", config_digest), &config[..])
+            .unwrap();
+
+        let manifest = serde_json::to_vec(&serde_json::json!([{
+            "Config": format!("blobs/sha256/{}", config_digest),
+            "RepoTags": ["test:latest"],
+            "Layers": layer_digests.iter().map(|d| format!("blobs/sha256/{}", d)).collect::<Vec<_>>(),
+        }]))
+        .unwrap();
+        let mut manifest_header = tar::Header::new_gnu();
+        manifest_header.set_size(manifest.len() as u64);
+        manifest_header.set_cksum();
+        builder.append_data(&mut manifest_header, "manifest.json", &manifest[..]).unwrap();
+
+        builder.finish().unwrap();
+        layer_digests
+    }
+
+    fn write_inspect(path: &Path, layer_digests: &[String]) {
+        let inspect = serde_json::json!([{
+            "Id": format!("sha256:{}", &layer_digests[0][..12]),
+            "RootFS": {
+                "Type": "layers",
+                "Layers": layer_digests.iter().map(|d| format!("sha256:{}", d)).collect::<Vec<_>>(),
+            },
+        }]);
+        fs::write(path, serde_json::to_vec(&inspect).unwrap()).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!(
+            "docker-image-patcher-main-test-{}-{}-{}",
+            std::process::id(),
+            n,
+            name
+        ))
+    }
+
+    /// 端到端覆盖 delta() -> patch() 在默认 `--verify` 开启时对一个真正变更过
+    /// 的层 (而非仅共享层) 的完整流程: 既要走通 FastCDC 分块重建, 也要让
+    /// 修补后的校验通过, 而不是像本轮修复前那样在压缩 blob 摘要比对上必然失败。
+    #[test]
+    fn delta_then_patch_round_trips_a_changed_layer_with_verify_on() {
+        let old_tar = temp_path("old.tar");
+        let new_tar = temp_path("new.tar");
+        let inspect_path = temp_path("inspect.json");
+
+        let shared_layer = b"shared layer content, unchanged across versions".repeat(64);
+        let old_layer_two = b"layer two, original content before the change".repeat(64);
+        let new_layer_two = b"layer two, totally different content after the change".repeat(64);
+
+        let old_digests = write_test_image(&old_tar, &[shared_layer.clone(), old_layer_two]);
+        write_inspect(&inspect_path, &old_digests);
+        write_test_image(&new_tar, &[shared_layer, new_layer_two.clone()]);
+
+        delta(&new_tar, Some(&inspect_path), None, None, None).expect("delta 生成失败");
+
+        let delta_path = new_tar.with_extension("delta");
+        patch(&old_tar, &delta_path, None, true).expect("patch 应在默认开启校验时成功");
+
+        let patched_tar = delta_path.with_extension("tar");
+        let layers = get_ordered_layers(&patched_tar, None).expect("读取修补后镜像的层列表失败");
+        assert_eq!(layers.len(), 2);
+
+        let reconstructed_blob = read_entry_bytes(&patched_tar, &format!("blobs/sha256/{}", layers[1]))
+            .unwrap()
+            .expect("修补后的镜像应包含重建的层");
+        let reconstructed_raw = gunzip(&reconstructed_blob).unwrap();
+        assert_eq!(reconstructed_raw, new_layer_two);
+
+        for p in [&old_tar, &new_tar, &inspect_path, &delta_path, &patched_tar] {
+            fs::remove_file(p).ok();
+        }
     }
 }
@@ -0,0 +1,185 @@
+//! 对 OCI image-layout 格式 tarball (`oci-layout` / `index.json`) 的支持
+//!
+//! `docker save` 产出的是扁平的 `manifest.json`; 而 `skopeo copy`、
+//! `ctr image export`、`docker buildx build --output type=oci` 等现代工具则
+//! 产出标准的 OCI image layout: 顶层 `index.json` 是一个镜像索引 (manifest
+//! list), 按平台指向各自的 `application/vnd.oci.image.manifest.v1+json`。
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::read_entry_bytes;
+
+#[derive(Debug, Deserialize)]
+pub struct OciDescriptor {
+    pub digest: String,
+    #[allow(dead_code)]
+    pub size: u64,
+    pub platform: Option<OciPlatform>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OciPlatform {
+    pub architecture: String,
+    pub os: String,
+    pub variant: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OciIndex {
+    pub manifests: Vec<OciDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OciManifest {
+    pub config: OciDescriptor,
+    pub layers: Vec<OciDescriptor>,
+}
+
+/// 根据平台选择器解析出的目标镜像: manifest 本身、配置与各层的摘要
+/// (均为不带 `sha256:` 前缀的文件名形式, 与仓库其余代码的约定一致)。
+pub struct ResolvedImage {
+    pub manifest_digest: String,
+    pub config_digest: String,
+    pub layer_digests: Vec<String>,
+}
+
+fn bare_digest(digest: &str) -> String {
+    digest.strip_prefix("sha256:").unwrap_or(digest).to_string()
+}
+
+/// tarball 根目录下存在 `oci-layout` 文件即认为是标准 OCI image layout。
+pub fn is_oci_layout(tar_path: &Path) -> Result<bool> {
+    Ok(read_entry_bytes(tar_path, "oci-layout")?.is_some())
+}
+
+/// 解析 `platform` 形如 `linux/amd64` 或 `linux/arm64/v8`。
+fn parse_platform(platform: &str) -> (String, String, Option<String>) {
+    let mut parts = platform.splitn(3, '/');
+    let os = parts.next().unwrap_or_default().to_string();
+    let arch = parts.next().unwrap_or_default().to_string();
+    let variant = parts.next().map(|v| v.to_string());
+    (os, arch, variant)
+}
+
+/// 从一组 manifest 描述符中按平台选择器选出目标镜像的描述符。
+///
+/// `platform` 为 `None` 时, 若只有一个镜像 manifest 则直接使用它,
+/// 否则要求调用方通过 `--platform` 明确指定其中之一。单独拆出便于测试。
+fn select_manifest<'a>(
+    manifests: &'a [OciDescriptor],
+    platform: Option<&str>,
+) -> Result<&'a OciDescriptor> {
+    if let Some(platform) = platform {
+        let (os, arch, variant) = parse_platform(platform);
+        manifests
+            .iter()
+            .find(|m| {
+                m.platform.as_ref().is_some_and(|p| {
+                    p.os == os
+                        && p.architecture == arch
+                        && variant.as_deref() == p.variant.as_deref()
+                })
+            })
+            .ok_or_else(|| anyhow::anyhow!("index.json 中未找到平台 {} 对应的镜像", platform))
+    } else if manifests.len() == 1 {
+        Ok(&manifests[0])
+    } else {
+        anyhow::bail!("index.json 中包含多个平台, 请使用 --platform 指定其中之一");
+    }
+}
+
+/// 按平台选择器从 `index.json` 中解析出目标镜像的 manifest、配置与层摘要。
+pub fn resolve_image(tar_path: &Path, platform: Option<&str>) -> Result<ResolvedImage> {
+    let index_bytes = read_entry_bytes(tar_path, "index.json")?
+        .ok_or_else(|| anyhow::anyhow!("OCI image layout 中不存在 index.json"))?;
+    let index: OciIndex = serde_json::from_slice(&index_bytes)?;
+
+    let descriptor = select_manifest(&index.manifests, platform)?;
+
+    let manifest_digest = bare_digest(&descriptor.digest);
+    let manifest_bytes = read_entry_bytes(tar_path, &format!("blobs/sha256/{}", manifest_digest))?
+        .ok_or_else(|| anyhow::anyhow!("未找到镜像 manifest blob: {}", manifest_digest))?;
+    let manifest: OciManifest = serde_json::from_slice(&manifest_bytes)?;
+
+    Ok(ResolvedImage {
+        manifest_digest,
+        config_digest: bare_digest(&manifest.config.digest),
+        layer_digests: manifest.layers.iter().map(|l| bare_digest(&l.digest)).collect(),
+    })
+}
+
+impl ResolvedImage {
+    /// 该镜像自身占用的 blob 摘要集合 (manifest + 配置 + 所有层), 用于在生成
+    /// 增量文件时过滤掉不属于所选平台的 blob。
+    pub fn owned_digests(&self) -> HashSet<String> {
+        let mut set: HashSet<String> = self.layer_digests.iter().cloned().collect();
+        set.insert(self.manifest_digest.clone());
+        set.insert(self.config_digest.clone());
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(digest: &str, os: &str, arch: &str, variant: Option<&str>) -> OciDescriptor {
+        OciDescriptor {
+            digest: digest.to_string(),
+            size: 0,
+            platform: Some(OciPlatform {
+                os: os.to_string(),
+                architecture: arch.to_string(),
+                variant: variant.map(|v| v.to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_platform_splits_os_arch_variant() {
+        assert_eq!(
+            parse_platform("linux/arm64/v8"),
+            ("linux".to_string(), "arm64".to_string(), Some("v8".to_string()))
+        );
+        assert_eq!(
+            parse_platform("linux/amd64"),
+            ("linux".to_string(), "amd64".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn select_manifest_picks_sole_entry_without_platform_arg() {
+        let manifests = vec![descriptor("sha256:aaa", "linux", "amd64", None)];
+        let chosen = select_manifest(&manifests, None).expect("应选中唯一条目");
+        assert_eq!(chosen.digest, "sha256:aaa");
+    }
+
+    #[test]
+    fn select_manifest_requires_platform_when_ambiguous() {
+        let manifests = vec![
+            descriptor("sha256:aaa", "linux", "amd64", None),
+            descriptor("sha256:bbb", "linux", "arm64", Some("v8")),
+        ];
+        assert!(select_manifest(&manifests, None).is_err());
+    }
+
+    #[test]
+    fn select_manifest_matches_requested_platform() {
+        let manifests = vec![
+            descriptor("sha256:aaa", "linux", "amd64", None),
+            descriptor("sha256:bbb", "linux", "arm64", Some("v8")),
+        ];
+        let chosen = select_manifest(&manifests, Some("linux/arm64/v8")).expect("应匹配到 arm64/v8");
+        assert_eq!(chosen.digest, "sha256:bbb");
+    }
+
+    #[test]
+    fn select_manifest_errors_on_unknown_platform() {
+        let manifests = vec![descriptor("sha256:aaa", "linux", "amd64", None)];
+        assert!(select_manifest(&manifests, Some("linux/arm64")).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+//! 修补产物的完整性校验
+//!
+//! `patch()` 会把旧 tarball 中的 blob 原样搬进重建后的镜像, 这里对每个被复用
+//! 的 blob 以及重建完成后的配置 / 各层做 sha256 核验, 避免损坏或被篡改的旧
+//! 镜像悄悄产出一份坏镜像。
+
+use anyhow::Result;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+
+/// 计算字节流的 sha256 摘要 (十六进制, 不带 `sha256:` 前缀)。
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// 核验 `data` 的 sha256 摘要是否等于 `expected_digest` (不带前缀的文件名形式),
+/// 并打印一行 OK/mismatch 日志。`label` 仅用于标识被校验的对象。
+pub fn verify_digest(label: &str, data: &[u8], expected_digest: &str) -> Result<()> {
+    let actual = sha256_hex(data);
+    if actual == expected_digest {
+        info!("校验 {} OK ({})", label, expected_digest);
+        Ok(())
+    } else {
+        error!(
+            "校验 {} mismatch: 期望 {}, 实际 {}",
+            label, expected_digest, actual
+        );
+        anyhow::bail!("完整性校验失败: {}", label)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_digest_accepts_matching_sha256() {
+        let data = b"hello delta";
+        let digest = sha256_hex(data);
+        assert!(verify_digest("test", data, &digest).is_ok());
+    }
+
+    #[test]
+    fn verify_digest_rejects_mismatch() {
+        let data = b"hello delta";
+        let wrong_digest = sha256_hex(b"something else");
+        assert!(verify_digest("test", data, &wrong_digest).is_err());
+    }
+}